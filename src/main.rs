@@ -29,14 +29,11 @@ type MyDisplay = Ssd1306<
 
 type MyQei = Qei<'static, embassy_stm32::peripherals::TIM2>;
 
-pub struct MyMovAvg {
-    acc: MovAvg<f32, f32, 20>,
-    last_count: f32,
-}
-
 pub mod qei_oversize;
 
 static ENCODER_RATE: f32 = (1024.0 * 4.0);
+// The display (and velocity sampling) timer runs at 20 Hz.
+static SAMPLE_DT: f32 = 1.0 / 20.0;
 
 #[rtic::app(device = crate::pac, peripherals= false, dispatchers = [EXTI0])]
 mod app {
@@ -66,14 +63,14 @@ mod app {
         primitives::{PrimitiveStyle, Rectangle},
         text::{Baseline, Text},
     };
-    use movavg::MovAvg;
     use panic_probe as _;
 
     use ssd1306::{mode::BufferedGraphicsMode, prelude::*, I2CDisplayInterface, Ssd1306};
     use tinybmp::Bmp;
 
     use crate::{
-        draw_numbers, draw_text, qei_oversize::QeiManager, MyDisplay, MyMovAvg, MyQei, ENCODER_RATE,
+        draw_numbers, draw_text, qei_oversize::QeiManager, MyDisplay, MyQei, ENCODER_RATE,
+        SAMPLE_DT,
     };
 
     #[shared]
@@ -84,8 +81,7 @@ mod app {
         display: MyDisplay,
         display_timer: Timer<'static, embassy_stm32::peripherals::TIM1>,
         qei_timer: MyQei,
-        qei_manager: QeiManager,
-        revs_per_minute: MyMovAvg,
+        qei_manager: QeiManager<u16>,
         top_left: Point,
         velocity: Point,
         bmp: Bmp<Rgb565, 'static>,
@@ -127,12 +123,7 @@ mod app {
         let qei_timer: Qei<'_, embassy_stm32::peripherals::TIM2> =
             Qei::new(p.TIM2, QeiPin::new_ch1(p.PA0), QeiPin::new_ch2(p.PA1));
 
-        let qei_manager = QeiManager::new();
-
-        let revs_per_minute: MyMovAvg = MyMovAvg {
-            acc: MovAvg::new(),
-            last_count: 0.0,
-        };
+        let qei_manager = QeiManager::with_alpha(0.1);
 
         // Update framerate
         let display_timer = Timer::new(p.TIM1);
@@ -149,7 +140,6 @@ mod app {
                 display_timer,
                 qei_timer,
                 qei_manager,
-                revs_per_minute,
                 display,
                 top_left: Point::new(5, 3),
                 velocity: Point::new(1, 1),
@@ -160,7 +150,7 @@ mod app {
         )
     }
 
-    #[task(binds = TIM1_UP, local = [display, top_left, velocity, display_timer, bmp, brightness, qei_timer, qei_manager, revs_per_minute])]
+    #[task(binds = TIM1_UP, local = [display, top_left, velocity, display_timer, bmp, brightness, qei_timer, qei_manager])]
     fn update(cx: update::Context) {
         let update::LocalResources {
             display,
@@ -171,26 +161,18 @@ mod app {
             brightness,
             qei_timer,
             qei_manager,
-            revs_per_minute,
             ..
         } = cx.local;
 
         let brr = qei_timer.count();
-        qei_manager.sample(brr);
+        qei_manager.sample_with_dt(brr, SAMPLE_DT).ok();
         let rev_count = qei_manager.count() as f32 / ENCODER_RATE;
-
-        let rev_diff = rev_count - revs_per_minute.last_count;
-        revs_per_minute.last_count = rev_count;
-
-        // если m[1] оборот за 1/n[20] секунды, то за секунду 1 * 20
-        // * seconds in minute;
-        let revs = rev_diff * 20. * 60.;
-        revs_per_minute.acc.feed(revs);
+        let rpm = qei_manager.revolutions_per_minute(ENCODER_RATE);
 
         // draw_text(display);
         display.clear_buffer();
 
-        draw_numbers(display, rev_count as f32, revs_per_minute.acc.get());
+        draw_numbers(display, rev_count as f32, rpm);
         // Write changes to the display
         display.flush().unwrap();
 
@@ -201,7 +183,6 @@ mod app {
 
 use embedded_graphics::Drawable;
 use lexical_core::format;
-use movavg::MovAvg;
 use ssd1306::{mode::BufferedGraphicsMode, size::DisplaySize128x32, Ssd1306};
 
 pub fn draw_text(display: &mut MyDisplay) {
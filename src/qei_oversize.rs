@@ -4,8 +4,8 @@
 //! This crate allows you to wrap a Qei counter in a larger type. This is usefull when your Timer
 //! counter is on 16 bit and spend a lot of time overflowing/underflowing.
 //! To use this wrapper you have to take samples regularly, but be carefull because the counter
-//! **should not** change for more than (2^16 - 1)/2 between two samples otherwise we can not
-//! detect overflows/underflows.
+//! **should not** change by half of the counter's range or more between two samples otherwise
+//! we can not detect overflows/underflows.
 //!
 //! The internal counter is an i64 which should be enough for most use cases.
 //!
@@ -13,78 +13,164 @@
 
 extern crate embedded_hal;
 
-const THRESHOLD: u16 = 32768;
+use embedded_hal::{Direction, Qei};
+
+/// A primitive unsigned integer type usable as the raw sample type of a [`QeiManager`].
+///
+/// This is implemented for `u8`, `u16` and `u32`, which covers the counter widths exposed by
+/// the timer peripherals found on most microcontrollers (8 bit basic timers, 16 bit general
+/// purpose timers, 32 bit advanced timers).
+pub trait Count: Copy + PartialEq + Default + Into<i64> {
+    /// The largest value representable by this type.
+    const MAX: Self;
+    /// Half of the full range of this type, i.e. `Self::MAX / 2 + 1`.
+    ///
+    /// Jumps between two samples strictly smaller than this can be unambiguously resolved as
+    /// either an overflow or an underflow. A jump of exactly this size is ambiguous and reported
+    /// as [`SamplingError::SampleTooFar`].
+    const HALF_RANGE: i64;
+    /// The number of distinct values this type can hold, i.e. `Self::MAX as i64 + 1`.
+    const FULL_RANGE: i64;
+
+    /// Wrapping (modular) subtraction, as implemented by the primitive integer types.
+    fn wrapping_sub(self, rhs: Self) -> Self;
+    /// Wrapping (modular) addition, as implemented by the primitive integer types.
+    fn wrapping_add(self, rhs: Self) -> Self;
+}
+
+macro_rules! impl_count {
+    ($t:ty) => {
+        impl Count for $t {
+            const MAX: Self = <$t>::max_value();
+            const HALF_RANGE: i64 = <$t>::max_value() as i64 / 2 + 1;
+            const FULL_RANGE: i64 = <$t>::max_value() as i64 + 1;
+
+            fn wrapping_sub(self, rhs: Self) -> Self {
+                <$t>::wrapping_sub(self, rhs)
+            }
+
+            fn wrapping_add(self, rhs: Self) -> Self {
+                <$t>::wrapping_add(self, rhs)
+            }
+        }
+    };
+}
+
+impl_count!(u8);
+impl_count!(u16);
+impl_count!(u32);
+
+/// Integer division rounding towards negative infinity, as opposed to the `/` operator which
+/// rounds towards zero.
+fn floor_div(a: i64, b: i64) -> i64 {
+    let d = a / b;
+    let r = a % b;
+    if r != 0 && (r < 0) != (b < 0) {
+        d - 1
+    } else {
+        d
+    }
+}
 
 /// The error returned when we update the internal counter
 // TODO : Implement all error traits
 #[derive(Debug)]
 pub enum SamplingError {
     /// The sample were taken too far apart : you have to make sure that the samples were at a
-    /// distance of (2^16-1)/2 maximum.
+    /// distance of less than half the counter's range.
     SampleTooFar,
 }
 
 /// Extend a Qei peripherals by tracking overflows and underflows.
+///
+/// `C` is the raw counter width reported by the underlying hardware (`u8`, `u16` or `u32`).
 #[derive(Debug)]
-pub struct QeiManager {
+pub struct QeiManager<C: Count = u16> {
     counter: i64,
-    previous_count: u16,
+    previous_count: C,
+    velocity_alpha: f32,
+    velocity_filtered: f32,
+    counts_per_detent: i64,
+    last_detent: i64,
 }
 
-impl QeiManager {
+impl<C: Count> QeiManager<C> {
     /// Create a new Qei from an existing one.
-    /// The implemntation assume that the counter can't change for more than (2^16-1)/2, because
-    /// otherwise we can't detect overflows/underflows
-    pub fn new() -> QeiManager {
+    /// The implemntation assume that the counter can't change by half of `C`'s range or more,
+    /// because otherwise we can't detect overflows/underflows
+    pub fn new() -> QeiManager<C> {
         QeiManager {
             counter: 0,
-            previous_count: 0,
+            previous_count: C::default(),
+            velocity_alpha: 1.0,
+            velocity_filtered: 0.0,
+            counts_per_detent: 1,
+            last_detent: 0,
+        }
+    }
+
+    /// Create a new Qei with velocity smoothing, see [`QeiManager::sample_with_dt`].
+    ///
+    /// `alpha` is the weight given to the latest instantaneous velocity sample in the
+    /// exponentially-weighted moving average : it must be in `(0, 1]`, where `1.0` disables
+    /// smoothing entirely and values close to `0` favour the older, smoother estimate.
+    pub fn with_alpha(alpha: f32) -> QeiManager<C> {
+        assert!(alpha > 0.0 && alpha <= 1.0);
+        QeiManager {
+            velocity_alpha: alpha,
+            ..Self::new()
         }
     }
 
+    /// Configure the number of raw counts that make up one detent, for use with
+    /// [`QeiManager::detents`], [`QeiManager::detent_delta`] and [`QeiManager::wrap_to`].
+    ///
+    /// This is a view over the raw counter : the counter itself keeps counting in raw units, it
+    /// is only grouped into detents when read through those three methods. Handy when the encoder
+    /// is used as a UI knob (e.g. a mechanical detent every 4 raw counts).
+    pub fn with_counts_per_detent(mut self, counts_per_detent: i64) -> Self {
+        assert!(counts_per_detent > 0);
+        self.counts_per_detent = counts_per_detent;
+        self.last_detent = self.detents();
+        self
+    }
+
     /// Take a new sample from the Qei and update the internal counter.
-    pub fn sample(&mut self, count: u16) -> Result<(), SamplingError> {
+    pub fn sample(&mut self, count: C) -> Result<(), SamplingError> {
         // let count = self.qei.count().into();
         self.update(count)
     }
 
     /// Take a new sample from the Qei and update the internal counter, unwrapping all errors.
-    pub fn sample_unwrap(&mut self, count: u16) {
+    pub fn sample_unwrap(&mut self, count: C) {
         // let count = self.qei.count().into();
         self.update(count).unwrap();
     }
 
     #[allow(dead_code)]
-    pub(crate) fn update_unwrap(&mut self, current_count: u16) {
+    pub(crate) fn update_unwrap(&mut self, current_count: C) {
         self.update(current_count).unwrap();
     }
 
-    pub(crate) fn update(&mut self, current_count: u16) -> Result<(), SamplingError> {
+    pub(crate) fn update(&mut self, current_count: C) -> Result<(), SamplingError> {
         if current_count == self.previous_count {
             return Ok(());
-        } else if self.previous_count < current_count {
-            if current_count - self.previous_count < THRESHOLD {
-                // Counterclockwise rotation no overflow
-                self.counter += (current_count - self.previous_count) as i64;
-            } else if current_count - self.previous_count > THRESHOLD {
-                // Clockwise rotation underflow
-                self.counter -= (u16::max_value() - current_count + self.previous_count + 1) as i64;
-            } else {
-                // The constraint was not resepected
-                return Err(SamplingError::SampleTooFar);
-            }
+        }
+
+        // `diff` is the delta from `previous_count` to `current_count`, modulo C::FULL_RANGE.
+        // Interpreting it against the half-range tells us whether the counter moved forward
+        // (possibly wrapping) or backward (possibly underflowing).
+        let diff: i64 = current_count.wrapping_sub(self.previous_count).into();
+
+        if diff < C::HALF_RANGE {
+            self.counter += diff;
+        } else if diff > C::HALF_RANGE {
+            self.counter -= C::FULL_RANGE - diff;
         } else {
-            if self.previous_count - current_count < THRESHOLD {
-                // Clockwise rotation, no overflow
-                self.counter -= (self.previous_count - current_count) as i64;
-            } else if self.previous_count - current_count > THRESHOLD {
-                // Counterclockwise rotation with overflow
-                self.counter += (u16::max_value() - self.previous_count + current_count + 1) as i64;
-            } else {
-                // The constraint was not respeccted
-                return Err(SamplingError::SampleTooFar);
-            }
+            // The constraint was not respected
+            return Err(SamplingError::SampleTooFar);
         }
+
         self.previous_count = current_count;
         Ok(())
     }
@@ -97,13 +183,207 @@ impl QeiManager {
     /// Resets the internal counter
     pub fn reset(&mut self) {
         self.counter = 0;
+        self.velocity_filtered = 0.0;
+        self.last_detent = 0;
+    }
+
+    /// Returns the number of whole detents the counter represents, floor-divided so that
+    /// decrements past zero behave correctly (e.g. with 4 counts per detent, a raw counter of
+    /// `-1` is detent `-1`, not `0`).
+    ///
+    /// The detent size defaults to `1` raw count ; configure it with
+    /// [`QeiManager::with_counts_per_detent`].
+    pub fn detents(&self) -> i64 {
+        floor_div(self.counter, self.counts_per_detent)
+    }
+
+    /// Returns the number of whole detents crossed since the last call to `detent_delta`.
+    pub fn detent_delta(&mut self) -> i64 {
+        let detents = self.detents();
+        let delta = detents - self.last_detent;
+        self.last_detent = detents;
+        delta
+    }
+
+    /// Returns the current detent position wrapped into `[0, range)`, using Euclidean
+    /// remainder ; handy to index a fixed-size list (a menu, a digit 0-9, ...) directly from the
+    /// encoder.
+    pub fn wrap_to(&self, range: i64) -> i64 {
+        self.detents().rem_euclid(range)
+    }
+
+    /// Take a new sample from the Qei, update the internal counter, and update the filtered
+    /// velocity estimate using the time elapsed since the previous sample.
+    ///
+    /// `dt` is that elapsed time, in seconds. If `dt` is `0.0` the counter is still updated but
+    /// the velocity estimate is left untouched, since the instantaneous velocity would require
+    /// dividing by zero.
+    pub fn sample_with_dt(&mut self, count: C, dt: f32) -> Result<(), SamplingError> {
+        let previous_counter = self.counter;
+        self.update(count)?;
+
+        if dt == 0.0 {
+            return Ok(());
+        }
+
+        let v_inst = (self.counter - previous_counter) as f32 / dt;
+        self.velocity_filtered =
+            self.velocity_alpha * v_inst + (1.0 - self.velocity_alpha) * self.velocity_filtered;
+        Ok(())
+    }
+
+    /// Returns the filtered velocity estimate, in counts per second.
+    ///
+    /// This is only meaningful once [`QeiManager::sample_with_dt`] has been used to take
+    /// samples ; plain [`QeiManager::sample`] never updates it.
+    pub fn counts_per_second(&self) -> f32 {
+        self.velocity_filtered
+    }
+
+    /// Returns the filtered velocity estimate, in revolutions per minute, given the number of
+    /// counter increments per revolution of the encoder (e.g. `1024 * 4` for a 1024 PPR
+    /// quadrature encoder).
+    pub fn revolutions_per_minute(&self, counts_per_revolution: f32) -> f32 {
+        self.counts_per_second() * 60.0 / counts_per_revolution
+    }
+}
+
+impl<C: Count> Default for QeiManager<C> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A [`QeiManager`] that owns its hardware [`Qei`] peripheral.
+///
+/// Unlike [`QeiManager`], which only ever sees the raw samples handed to it, this wrapper reads
+/// `count()` itself and, when a delta lands exactly on the ambiguous half-range boundary, breaks
+/// the tie using the peripheral's `direction()` instead of returning
+/// [`SamplingError::SampleTooFar`]. This makes the half-range boundary fully deterministic, at
+/// the cost of owning the peripheral.
+#[derive(Debug)]
+pub struct QeiManagerHw<Q: Qei>
+where
+    Q::Count: Count,
+{
+    qei: Q,
+    manager: QeiManager<Q::Count>,
+}
+
+impl<Q: Qei> QeiManagerHw<Q>
+where
+    Q::Count: Count,
+{
+    /// Wrap an existing Qei peripheral.
+    pub fn new(qei: Q) -> Self {
+        QeiManagerHw {
+            qei,
+            manager: QeiManager::new(),
+        }
+    }
+
+    /// Read the peripheral and update the internal counter.
+    ///
+    /// When the delta since the last sample is exactly the ambiguous half-range, the
+    /// peripheral's `direction()` is used to resolve it instead of failing.
+    pub fn sample(&mut self) {
+        let current_count = self.qei.count();
+        if current_count == self.manager.previous_count {
+            return;
+        }
+
+        let diff: i64 = current_count
+            .wrapping_sub(self.manager.previous_count)
+            .into();
+
+        if diff == Q::Count::HALF_RANGE {
+            match self.qei.direction() {
+                Direction::Upcounting => self.manager.counter += diff,
+                Direction::Downcounting => self.manager.counter -= Q::Count::FULL_RANGE - diff,
+            }
+            self.manager.previous_count = current_count;
+        } else {
+            // Any non-ambiguous delta is handled identically to the plain `QeiManager`.
+            self.manager.update(current_count).unwrap();
+        }
+    }
+
+    /// Returns the internal counter value
+    pub fn count(&self) -> i64 {
+        self.manager.count()
+    }
+
+    /// Resets the internal counter
+    pub fn reset(&mut self) {
+        self.manager.reset();
+    }
+}
+
+/// An exact, ISR-driven counting mode that tracks timer overflows directly instead of inferring
+/// them from periodic samples.
+///
+/// This removes the requirement that consecutive samples be less than half the counter's range
+/// apart (the constraint [`QeiManager`] and [`QeiManagerHw`] are subject to), at the cost of
+/// needing to service the timer's update/overflow interrupt and call
+/// [`QeiManagerExact::on_overflow`] from it. The polling-based managers remain the simpler
+/// fallback for users who can't or don't want to do that.
+#[derive(Debug)]
+pub struct QeiManagerExact<C: Count> {
+    overflow_count: i64,
+    current_count: C,
+}
+
+impl<C: Count> QeiManagerExact<C> {
+    /// Create a new exact counter.
+    pub fn new() -> Self {
+        QeiManagerExact {
+            overflow_count: 0,
+            current_count: C::default(),
+        }
+    }
+
+    /// Record a timer overflow from the timer's update/overflow interrupt.
+    ///
+    /// Call this with `Direction::Upcounting` when the counter wrapped past its maximum value,
+    /// or `Direction::Downcounting` when it wrapped past zero.
+    pub fn on_overflow(&mut self, direction: Direction) {
+        match direction {
+            Direction::Upcounting => self.overflow_count += 1,
+            Direction::Downcounting => self.overflow_count -= 1,
+        }
+    }
+
+    /// Record the current raw counter value, without any threshold logic.
+    ///
+    /// Overflow tracking is entirely handled by [`QeiManagerExact::on_overflow`], so this can be
+    /// called as often as needed (e.g. every time a position is read) regardless of how far the
+    /// counter has moved since the last call.
+    pub fn update_exact(&mut self, current_count: C) {
+        self.current_count = current_count;
+    }
+
+    /// Returns the exact counter value
+    pub fn count(&self) -> i64 {
+        self.overflow_count * C::FULL_RANGE + self.current_count.into()
+    }
+
+    /// Resets the internal counter
+    pub fn reset(&mut self) {
+        self.overflow_count = 0;
+        self.current_count = C::default();
+    }
+}
+
+impl<C: Count> Default for QeiManagerExact<C> {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
 #[cfg(test)]
 mod test {
     use embedded_hal::{Direction, Qei};
-    use QeiManager;
+    use {QeiManager, QeiManagerExact, QeiManagerHw};
 
     struct DummyQei {}
 
@@ -117,20 +397,35 @@ mod test {
         }
     }
 
+    struct FakeQei {
+        count: u16,
+        direction: Direction,
+    }
+
+    impl Qei for FakeQei {
+        type Count = u16;
+        fn count(&self) -> u16 {
+            self.count
+        }
+        fn direction(&self) -> Direction {
+            self.direction
+        }
+    }
+
     #[test]
     fn no_trap() {
-        let mut qei = QeiManager::new();
+        let mut qei = QeiManager::<u16>::new();
         qei.update_unwrap(55);
         assert_eq!(qei.count(), 55)
     }
 
     #[test]
     fn underflow() {
-        let mut qei = QeiManager::new();
+        let mut qei = QeiManager::<u16>::new();
         qei.update_unwrap(5);
         qei.update_unwrap(65532);
         assert_eq!(qei.count(), -4); // -4 et pas -3
-        let mut qei = QeiManager::new();
+        let mut qei = QeiManager::<u16>::new();
         qei.update_unwrap(5);
         qei.update_unwrap(65535);
         assert_eq!(qei.count(), -1);
@@ -138,11 +433,11 @@ mod test {
 
     #[test]
     fn overflow() {
-        let mut qei = QeiManager::new();
+        let mut qei = QeiManager::<u16>::new();
         qei.update_unwrap(65522);
         qei.update_unwrap(55);
         assert_eq!(qei.count(), 55_i64);
-        let mut qei = QeiManager::new();
+        let mut qei = QeiManager::<u16>::new();
         qei.update_unwrap(65535);
         qei.update_unwrap(0);
         assert_eq!(qei.count(), 0);
@@ -153,12 +448,12 @@ mod test {
 
     #[test]
     fn middle_values() {
-        let mut qei = QeiManager::new();
+        let mut qei = QeiManager::<u16>::new();
         qei.update_unwrap(13546);
         qei.update_unwrap(13500);
         qei.update_unwrap(15678);
         assert_eq!(qei.count(), 15678);
-        let mut qei = QeiManager::new();
+        let mut qei = QeiManager::<u16>::new();
         qei.update_unwrap(16000);
         qei.update_unwrap(15000);
         assert_eq!(qei.count(), 15000);
@@ -166,7 +461,7 @@ mod test {
 
     #[test]
     fn going_back() {
-        let mut qei = QeiManager::new();
+        let mut qei = QeiManager::<u16>::new();
         qei.update_unwrap(65489);
         qei.update_unwrap(65000);
         assert_eq!(qei.count(), -536); // -536 et pas 535 : 65000 - (-536) doit faire 0
@@ -178,7 +473,7 @@ mod test {
 
     #[test]
     fn no_changes() {
-        let mut qei = QeiManager::new();
+        let mut qei = QeiManager::<u16>::new();
         qei.update_unwrap(0);
         qei.update_unwrap(0);
         assert_eq!(qei.count(), 0);
@@ -186,11 +481,11 @@ mod test {
 
     #[test]
     fn small_changes() {
-        let mut qei = QeiManager::new();
+        let mut qei = QeiManager::<u16>::new();
         qei.update_unwrap(0);
         qei.update_unwrap(u16::max_value());
         assert_eq!(qei.count(), -1);
-        let mut qei = QeiManager::new();
+        let mut qei = QeiManager::<u16>::new();
         qei.update_unwrap(u16::max_value());
         qei.update_unwrap(0);
         assert_eq!(qei.count(), 0);
@@ -201,4 +496,135 @@ mod test {
         qei.update_unwrap(65534);
         assert_eq!(qei.count(), -2);
     }
+
+    #[test]
+    fn u8_counter() {
+        let mut qei = QeiManager::<u8>::new();
+        qei.update_unwrap(250);
+        qei.update_unwrap(5);
+        assert_eq!(qei.count(), 5);
+        qei.update_unwrap(200);
+        assert_eq!(qei.count(), -56);
+    }
+
+    /// Floating point equality, to within a small epsilon : the velocity estimates below are
+    /// exact given their inputs, but comparing `f32`s with `==` is fragile and clippy-unfriendly.
+    fn approx_eq(a: f32, b: f32) -> bool {
+        (a - b).abs() < 1e-6
+    }
+
+    #[test]
+    fn velocity_no_smoothing() {
+        let mut qei = QeiManager::<u16>::new();
+        qei.sample_with_dt(0, 1.0).unwrap();
+        qei.sample_with_dt(1024, 0.5).unwrap();
+        assert!(approx_eq(qei.counts_per_second(), 2048.0));
+        assert!(approx_eq(qei.revolutions_per_minute(1024.0), 120.0));
+    }
+
+    #[test]
+    fn velocity_skips_zero_dt() {
+        let mut qei = QeiManager::<u16>::new();
+        qei.sample_with_dt(0, 1.0).unwrap();
+        qei.sample_with_dt(100, 1.0).unwrap();
+        assert!(approx_eq(qei.counts_per_second(), 100.0));
+        // A zero dt must not divide-by-zero nor perturb the filtered estimate.
+        qei.sample_with_dt(200, 0.0).unwrap();
+        assert_eq!(qei.count(), 200);
+        assert!(approx_eq(qei.counts_per_second(), 100.0));
+    }
+
+    #[test]
+    fn velocity_reset() {
+        let mut qei = QeiManager::<u16>::new();
+        qei.sample_with_dt(0, 1.0).unwrap();
+        qei.sample_with_dt(100, 1.0).unwrap();
+        qei.reset();
+        assert_eq!(qei.count(), 0);
+        assert!(approx_eq(qei.counts_per_second(), 0.0));
+    }
+
+    #[test]
+    fn u32_counter() {
+        let mut qei = QeiManager::<u32>::new();
+        qei.update_unwrap(u32::max_value() - 10);
+        qei.update_unwrap(5);
+        assert_eq!(qei.count(), 5);
+    }
+
+    #[test]
+    fn hw_resolves_half_range_upcounting() {
+        let qei = FakeQei {
+            count: 32768,
+            direction: Direction::Upcounting,
+        };
+        let mut qei = QeiManagerHw::new(qei);
+        qei.sample();
+        assert_eq!(qei.count(), 32768);
+    }
+
+    #[test]
+    fn hw_resolves_half_range_downcounting() {
+        let qei = FakeQei {
+            count: 32768,
+            direction: Direction::Downcounting,
+        };
+        let mut qei = QeiManagerHw::new(qei);
+        qei.sample();
+        assert_eq!(qei.count(), -32768);
+    }
+
+    #[test]
+    fn exact_tracks_overflows() {
+        let mut qei = QeiManagerExact::<u16>::new();
+        qei.update_exact(65000);
+        assert_eq!(qei.count(), 65000);
+        qei.on_overflow(Direction::Upcounting);
+        qei.update_exact(500);
+        assert_eq!(qei.count(), 65536 + 500);
+        qei.on_overflow(Direction::Downcounting);
+        qei.on_overflow(Direction::Downcounting);
+        qei.update_exact(0);
+        assert_eq!(qei.count(), -65536);
+    }
+
+    #[test]
+    fn exact_reset() {
+        let mut qei = QeiManagerExact::<u16>::new();
+        qei.on_overflow(Direction::Upcounting);
+        qei.update_exact(5);
+        qei.reset();
+        assert_eq!(qei.count(), 0);
+    }
+
+    #[test]
+    fn detents_floor_division() {
+        let mut qei = QeiManager::<u16>::new().with_counts_per_detent(4);
+        qei.update_unwrap(9);
+        assert_eq!(qei.count(), 9);
+        assert_eq!(qei.detents(), 2);
+        qei.update_unwrap(65535); // counter -= 10, counter == -1
+        assert_eq!(qei.count(), -1);
+        assert_eq!(qei.detents(), -1); // floor(-1 / 4) == -1, not 0
+    }
+
+    #[test]
+    fn detent_delta_tracks_crossings() {
+        let mut qei = QeiManager::<u16>::new().with_counts_per_detent(4);
+        assert_eq!(qei.detent_delta(), 0);
+        qei.update_unwrap(9); // detents() == 2
+        assert_eq!(qei.detent_delta(), 2);
+        assert_eq!(qei.detent_delta(), 0);
+        qei.update_unwrap(1); // counter == 1, detents() == 0
+        assert_eq!(qei.detent_delta(), -2);
+    }
+
+    #[test]
+    fn wrap_to_indexes_a_fixed_list() {
+        let mut qei = QeiManager::<u16>::new().with_counts_per_detent(4);
+        qei.update_unwrap(65535); // counter == -1, detents() == -1
+        assert_eq!(qei.wrap_to(10), 9);
+        qei.update_unwrap(49); // counter == 49, detents() == 12
+        assert_eq!(qei.wrap_to(10), 2);
+    }
 }